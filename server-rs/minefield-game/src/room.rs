@@ -1,4 +1,11 @@
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
 use failure::{Error, Fail};
+use prometheus::{IntGauge, Registry};
 use serde::{Deserialize, Serialize};
 
 use crate::game::Game;
@@ -12,28 +19,172 @@ pub enum RoomError {
     GameNotStarted,
     #[fail(display = "game finished")]
     GameFinished,
+    #[fail(display = "chat message too long")]
+    ChatTooLong,
+    #[fail(display = "chat rate limit exceeded")]
+    ChatRateLimited,
+    #[fail(display = "match series already concluded")]
+    SeriesConcluded,
+}
+
+/// Number of hand wins needed to conclude a best-of-series match.
+const POINTS_TO_WIN: i32 = 2;
+
+/// How long a seat may stay vacant mid-game before its occupant forfeits.
+const DEFAULT_FORFEIT_GRACE: Duration = Duration::from_secs(60);
+
+/// Longest chat message accepted from a player, in characters.
+const CHAT_MAX_LEN: usize = 500;
+
+/// Minimum time a player must wait between two chat messages.
+const CHAT_MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A `Room` plays a series of hands rather than a single `Game`. Between
+/// hands it sits in `WaitingForRematch` until both players opt back in.
+/// `Concluded` is a terminal state distinct from `match_state` being `None`:
+/// the latter means "never started", the former means "series is over" —
+/// conflating them would let a stranger join a finished room's leftover seat.
+#[derive(Serialize, Deserialize)]
+enum MatchState {
+    Playing(Game),
+    WaitingForRematch([bool; 2]),
+    Concluded,
+}
+
+/// Shared prometheus gauges tracking how many rooms, in-progress games and
+/// connected players currently exist. One `RoomMetrics` is registered per
+/// process and handed to every `Room`.
+#[derive(Clone)]
+pub struct RoomMetrics {
+    rooms_active: IntGauge,
+    games_in_progress: IntGauge,
+    players_connected: IntGauge,
+}
+
+impl RoomMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, Error> {
+        let rooms_active = IntGauge::new("minefield_rooms_active", "Rooms currently open")?;
+        let games_in_progress = IntGauge::new(
+            "minefield_games_in_progress",
+            "Hands currently being played",
+        )?;
+        let players_connected = IntGauge::new(
+            "minefield_players_connected",
+            "Players currently connected to a room",
+        )?;
+
+        registry.register(Box::new(rooms_active.clone()))?;
+        registry.register(Box::new(games_in_progress.clone()))?;
+        registry.register(Box::new(players_connected.clone()))?;
+
+        Ok(RoomMetrics {
+            rooms_active,
+            games_in_progress,
+            players_connected,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Room {
-    game: Option<Game>,
+    match_state: Option<MatchState>,
     #[serde(skip)]
     user_ids: [Option<usize>; 2],
     nicks: [String; 2],
     pub room_key: String,
     pub player_keys: [String; 2],
     messages: [Vec<Msg>; 2],
+    #[serde(skip)]
+    spectators: Vec<usize>,
+    spectator_messages: Vec<Msg>,
+    scores: [i32; 2],
+    games_played: u32,
+    forfeit_grace: Duration,
+    #[serde(skip)]
+    last_seen: [Option<Instant>; 2],
+    #[serde(skip)]
+    last_chat: [Option<Instant>; 2],
+    #[serde(skip)]
+    metrics: Option<RoomMetrics>,
+    #[serde(skip)]
+    store: Option<RoomStore>,
+}
+
+/// Folds one `messages()` batch (each player's own view of the turn,
+/// duplicated StartMove/phase messages included) into the single feed a
+/// spectator sees: each distinct message kind appears once, and any message
+/// still carrying a secret hand has that hand redacted.
+fn spectator_view(batch: &[(usize, Msg)]) -> Vec<Msg> {
+    let mut seen = HashSet::new();
+    batch
+        .iter()
+        .filter(|(_, msg)| seen.insert(mem::discriminant(msg)))
+        .map(|(_, msg)| redact_hand(msg.clone()))
+        .collect()
+}
+
+/// Clears the still-secret tile list carried by `Msg::PhaseOne` and the
+/// chosen hand carried by `Msg::Hand`, leaving every other field (and every
+/// other message kind) untouched.
+fn redact_hand(mut msg: Msg) -> Msg {
+    match msg {
+        Msg::PhaseOne { ref mut tiles, .. } => tiles.clear(),
+        Msg::Hand { ref mut hand, .. } => hand.clear(),
+        _ => {}
+    }
+    msg
 }
 
 impl Room {
     pub fn new(user_id: usize, nick: String) -> Self {
         Room {
-            game: None,
+            match_state: None,
             user_ids: [Some(user_id), None],
             nicks: [nick, "".to_owned()],
             room_key: Self::gen_key(),
             player_keys: [Self::gen_key(), Self::gen_key()],
             messages: [vec![], vec![]],
+            spectators: vec![],
+            spectator_messages: vec![],
+            scores: [0, 0],
+            games_played: 0,
+            forfeit_grace: DEFAULT_FORFEIT_GRACE,
+            last_seen: [Some(Instant::now()), None],
+            last_chat: [None, None],
+            metrics: None,
+            store: None,
+        }
+    }
+
+    /// Attaches a shared `RoomMetrics` handle and accounts for this room's
+    /// current state in the gauges. Call right after `new`, or after
+    /// reloading a room from a `RoomStore` on startup.
+    pub fn attach_metrics(&mut self, metrics: RoomMetrics) {
+        metrics.rooms_active.inc();
+        for _ in self.user_ids.iter().flatten() {
+            metrics.players_connected.inc();
+        }
+        if matches!(self.match_state, Some(MatchState::Playing(_))) {
+            metrics.games_in_progress.inc();
+        }
+        self.metrics = Some(metrics);
+    }
+
+    /// Attaches a `RoomStore` so this room's state is snapshotted through on
+    /// every mutation. Call right after `new`, or after reloading a room via
+    /// `RoomStore::load_all` on startup.
+    pub fn attach_store(&mut self, store: RoomStore) {
+        self.store = Some(store);
+        self.persist();
+    }
+
+    /// Snapshots this room via its attached `RoomStore`, if any. A no-op
+    /// until `attach_store` has been called.
+    fn persist(&self) {
+        if let Some(store) = &self.store {
+            if let Err(err) = store.save(self) {
+                eprintln!("failed to persist room {}: {}", self.room_key, err);
+            }
         }
     }
 
@@ -49,11 +200,15 @@ impl Room {
     }
 
     pub fn describe(&self) -> Option<PGame> {
-        match self.game.as_ref() {
-            Some(game) if game.finished => None,
-            Some(_) => Some(PGame::Game {
-                nicks: self.nicks.clone(),
-            }),
+        match self.match_state.as_ref() {
+            Some(MatchState::Playing(_)) | Some(MatchState::WaitingForRematch(_)) => {
+                Some(PGame::Game {
+                    nicks: self.nicks.clone(),
+                })
+            }
+            // The series is over; don't advertise this seat as a fresh
+            // open invite the way a never-started room would be.
+            Some(MatchState::Concluded) => None,
             None if self.user_ids[0].is_some() => Some(PGame::Player {
                 nick: self.nicks[0].clone(),
                 key: self.room_key.clone(),
@@ -63,8 +218,18 @@ impl Room {
     }
 
     pub fn beat(&mut self) -> Vec<(usize, Msg)> {
-        match self.game.as_mut() {
-            Some(game) if !game.finished => {
+        let result = self.beat_impl();
+        self.persist();
+        result
+    }
+
+    fn beat_impl(&mut self) -> Vec<(usize, Msg)> {
+        if let Some(absent) = self.absent_seat_past_grace() {
+            return self.forfeit(absent);
+        }
+
+        match self.match_state.as_mut() {
+            Some(MatchState::Playing(game)) if !game.finished => {
                 game.beat();
                 self.messages()
             }
@@ -72,25 +237,91 @@ impl Room {
         }
     }
 
+    /// Returns the seat that has been vacant longer than `forfeit_grace`
+    /// while a hand is in progress or the room is waiting on it for a
+    /// rematch, if any. Without the latter, a player who vanishes instead of
+    /// sending `RequestRematch` would leave the room hung forever.
+    fn absent_seat_past_grace(&self) -> Option<usize> {
+        let waiting_on_seats = matches!(
+            self.match_state,
+            Some(MatchState::Playing(ref game)) if !game.finished
+        ) || matches!(self.match_state, Some(MatchState::WaitingForRematch(_)));
+        if !waiting_on_seats {
+            return None;
+        }
+
+        (0..2).find(|&i| {
+            self.user_ids[i].is_none()
+                && self.last_seen[i].map_or(false, |t| t.elapsed() >= self.forfeit_grace)
+        })
+    }
+
+    /// Ends the hand in progress (or, if no hand is in progress, the series
+    /// itself) as a forfeit, attributing the loss to the player in `absent`.
+    fn forfeit(&mut self, absent: usize) -> Vec<(usize, Msg)> {
+        let msg = Msg::Abort {
+            culprit: absent,
+            description: "disconnected too long".to_owned(),
+        };
+
+        let mut result = vec![];
+        for i in 0..2 {
+            self.messages[i].push(msg.clone());
+            if let Some(user_id) = self.user_ids[i] {
+                result.push((user_id, msg.clone()));
+            }
+        }
+        self.spectator_messages.push(msg.clone());
+        for &user_id in &self.spectators {
+            result.push((user_id, msg.clone()));
+        }
+
+        match self.match_state {
+            Some(MatchState::Playing(_)) => {
+                result.append(&mut self.finish_game(Some(1 - absent)));
+            }
+            Some(MatchState::WaitingForRematch(_)) => {
+                // There's no hand in progress to score: the absent player
+                // simply forfeits the rest of the series.
+                self.match_state = Some(MatchState::Concluded);
+            }
+            Some(MatchState::Concluded) | None => {}
+        }
+
+        result
+    }
+
     pub fn started(&self) -> bool {
-        self.game.is_some()
+        self.match_state.is_some()
     }
 
     pub fn finished(&self) -> bool {
-        let game_finished = match self.game.as_ref() {
-            Some(game) => game.finished,
-            None => true,
-        };
-        game_finished && self.user_ids[0].is_none() && self.user_ids[1].is_none()
+        let series_over = matches!(self.match_state, None | Some(MatchState::Concluded));
+        series_over && self.user_ids[0].is_none() && self.user_ids[1].is_none()
     }
 
     pub fn connect(&mut self, user_id: usize, nick: String) -> Result<Vec<(usize, Msg)>, Error> {
-        if self.user_ids[1].is_some() || self.game.is_some() {
+        let result = self.connect_impl(user_id, nick);
+        self.persist();
+        result
+    }
+
+    fn connect_impl(&mut self, user_id: usize, nick: String) -> Result<Vec<(usize, Msg)>, Error> {
+        match self.match_state {
+            Some(MatchState::Concluded) => return Err(RoomError::SeriesConcluded.into()),
+            Some(_) => return Err(RoomError::AlreadyJoined.into()),
+            None => {}
+        }
+        if self.user_ids[1].is_some() {
             return Err(RoomError::AlreadyJoined.into());
         }
 
         self.user_ids[1] = Some(user_id);
         self.nicks[1] = nick;
+        self.last_seen[1] = Some(Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.players_connected.inc();
+        }
 
         assert!(self.user_ids[0] != self.user_ids[1]);
 
@@ -107,12 +338,21 @@ impl Room {
             }
         }
 
+        messages.append(&mut self.start_game());
+        Ok(messages)
+    }
+
+    /// Starts a fresh `Game` for this series, either for the first hand or
+    /// after both players have requested a rematch.
+    fn start_game(&mut self) -> Vec<(usize, Msg)> {
         let mut game = Game::new(&mut rand::thread_rng());
         game.on_start();
-        self.game = Some(game);
+        self.match_state = Some(MatchState::Playing(game));
+        if let Some(metrics) = &self.metrics {
+            metrics.games_in_progress.inc();
+        }
 
-        messages.append(&mut self.messages());
-        Ok(messages)
+        self.messages()
     }
 
     pub fn rejoin(&mut self, user_id: usize, i: usize) -> Result<Vec<(usize, Msg)>, Error> {
@@ -121,6 +361,10 @@ impl Room {
         }
 
         self.user_ids[i] = Some(user_id);
+        self.last_seen[i] = Some(Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.players_connected.inc();
+        }
         assert!(self.user_ids[0] != self.user_ids[1]);
 
         let mut replayed: Vec<(usize, Msg)> = self.messages[i]
@@ -135,7 +379,7 @@ impl Room {
             })
             .collect();
 
-        if let Some(ref game) = self.game {
+        if let Some(MatchState::Playing(ref game)) = self.match_state {
             if !game.finished {
                 if let Some(msg) = game.rejoin_msg(i) {
                     replayed.push((user_id, Msg::Replay { msg: Box::new(msg) }))
@@ -146,15 +390,65 @@ impl Room {
         Ok(replayed)
     }
 
+    /// Like `rejoin`, but for a third party who never holds a `player_key`:
+    /// replays the spectator-safe history and then receives every future
+    /// game message.
+    pub fn connect_spectator(&mut self, user_id: usize) -> Vec<(usize, Msg)> {
+        let replayed = self
+            .spectator_messages
+            .iter()
+            .map(|msg| {
+                (
+                    user_id,
+                    Msg::Replay {
+                        msg: Box::new(msg.clone()),
+                    },
+                )
+            })
+            .collect();
+
+        self.spectators.push(user_id);
+        replayed
+    }
+
+    pub fn disconnect_spectator(&mut self, user_id: usize) {
+        self.spectators.retain(|&id| id != user_id);
+    }
+
     pub fn disconnect(&mut self, user_id: usize) {
         let i = self.find_player(user_id).unwrap();
         self.user_ids[i] = None;
+        // Start the forfeit grace period from the moment of disconnect.
+        self.last_seen[i] = Some(Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.players_connected.dec();
+        }
+        self.persist();
     }
 
     pub fn on_message(&mut self, user_id: usize, msg: Msg) -> Result<Vec<(usize, Msg)>, Error> {
+        let result = self.on_message_impl(user_id, msg);
+        self.persist();
+        result
+    }
+
+    fn on_message_impl(&mut self, user_id: usize, msg: Msg) -> Result<Vec<(usize, Msg)>, Error> {
         let i = self.find_player(user_id).unwrap();
+        self.last_seen[i] = Some(Instant::now());
+
+        match msg {
+            Msg::RequestRematch => return self.request_rematch(i),
+            Msg::Chat { text, .. } => return self.chat(i, text),
+            _ => {}
+        }
 
-        let game = self.game.as_mut().ok_or(RoomError::GameNotStarted)?;
+        let game = match self.match_state.as_mut() {
+            Some(MatchState::Playing(game)) => game,
+            Some(MatchState::WaitingForRematch(_)) | Some(MatchState::Concluded) => {
+                return Err(RoomError::GameFinished.into())
+            }
+            None => return Err(RoomError::GameNotStarted.into()),
+        };
         if game.finished {
             return Err(RoomError::GameFinished.into());
         }
@@ -163,6 +457,51 @@ impl Room {
         Ok(self.messages())
     }
 
+    /// Both players must `RequestRematch` (mirroring a join/accept
+    /// handshake) before the next hand of the series begins.
+    fn request_rematch(&mut self, i: usize) -> Result<Vec<(usize, Msg)>, Error> {
+        let ready = match self.match_state.as_mut() {
+            Some(MatchState::WaitingForRematch(ready)) => ready,
+            Some(MatchState::Playing(_)) | Some(MatchState::Concluded) | None => {
+                return Err(RoomError::GameNotStarted.into())
+            }
+        };
+        ready[i] = true;
+
+        if ready[0] && ready[1] {
+            Ok(self.start_game())
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Relays a chat message to the opponent and buffers it for both
+    /// players so a reconnecting client sees the backlog via `rejoin`.
+    fn chat(&mut self, i: usize, text: String) -> Result<Vec<(usize, Msg)>, Error> {
+        if text.chars().count() > CHAT_MAX_LEN {
+            return Err(RoomError::ChatTooLong.into());
+        }
+
+        let now = Instant::now();
+        if let Some(last) = self.last_chat[i] {
+            if now.duration_since(last) < CHAT_MIN_INTERVAL {
+                return Err(RoomError::ChatRateLimited.into());
+            }
+        }
+        self.last_chat[i] = Some(now);
+
+        let msg = Msg::Chat { from: i, text };
+        for j in 0..2 {
+            self.messages[j].push(msg.clone());
+        }
+
+        let opponent = 1 - i;
+        Ok(match self.user_ids[opponent] {
+            Some(user_id) => vec![(user_id, msg)],
+            None => vec![],
+        })
+    }
+
     fn find_player(&self, user_id: usize) -> Option<usize> {
         if self.user_ids[0] == Some(user_id) {
             Some(0)
@@ -175,8 +514,23 @@ impl Room {
 
     fn messages(&mut self) -> Vec<(usize, Msg)> {
         let mut result = vec![];
-        let game = self.game.as_mut().unwrap();
-        for (i, msg) in game.messages().into_iter() {
+        let game = match self.match_state.as_mut() {
+            Some(MatchState::Playing(game)) => game,
+            _ => return result,
+        };
+        let batch: Vec<(usize, Msg)> = game.messages().into_iter().collect();
+
+        // `batch` can hold each player's view of the same turn twice (once
+        // per secret hand) plus duplicated StartMove/phase messages; fold it
+        // into a single spectator-safe feed before fanning it out.
+        for msg in spectator_view(&batch) {
+            self.spectator_messages.push(msg.clone());
+            for &user_id in &self.spectators {
+                result.push((user_id, msg.clone()));
+            }
+        }
+
+        for (i, msg) in batch.into_iter() {
             // Add messsage for replaying
             self.messages[i].push(msg.clone());
 
@@ -185,10 +539,169 @@ impl Room {
                 result.push((user_id, msg));
             }
         }
+
+        if matches!(self.match_state, Some(MatchState::Playing(ref game)) if game.finished) {
+            let winner = match self.match_state.as_ref() {
+                Some(MatchState::Playing(game)) => game.winner(),
+                _ => None,
+            };
+            result.append(&mut self.finish_game(winner));
+        }
+
+        result
+    }
+
+    /// Scores the just-finished hand, broadcasts the running `MatchScore`,
+    /// and either closes out the series or moves the room into
+    /// `WaitingForRematch` for the next hand.
+    fn finish_game(&mut self, winner: Option<usize>) -> Vec<(usize, Msg)> {
+        if let Some(winner) = winner {
+            self.scores[winner] += 1;
+        }
+        self.games_played += 1;
+        if let Some(metrics) = &self.metrics {
+            metrics.games_in_progress.dec();
+        }
+
+        let msg = Msg::MatchScore {
+            scores: self.scores,
+            games_played: self.games_played,
+        };
+        self.spectator_messages.push(msg.clone());
+
+        let mut result = vec![];
+        for i in 0..2 {
+            self.messages[i].push(msg.clone());
+            if let Some(user_id) = self.user_ids[i] {
+                result.push((user_id, msg.clone()));
+            }
+        }
+        for &user_id in &self.spectators {
+            result.push((user_id, msg.clone()));
+        }
+
+        self.match_state = if self.scores[0] >= POINTS_TO_WIN || self.scores[1] >= POINTS_TO_WIN {
+            Some(MatchState::Concluded)
+        } else {
+            Some(MatchState::WaitingForRematch([false, false]))
+        };
+
+        // A vacant seat's `last_seen` may already be past `forfeit_grace` —
+        // that's what just forfeited the hand. Reset it here so the absent
+        // player gets a full, fresh window to reconnect and request a
+        // rematch instead of having the series forfeited on the very next
+        // `beat()` off the same stale timestamp.
+        if matches!(self.match_state, Some(MatchState::WaitingForRematch(_))) {
+            let now = Instant::now();
+            for i in 0..2 {
+                if self.user_ids[i].is_none() {
+                    self.last_seen[i] = Some(now);
+                }
+            }
+        }
+
         result
     }
 }
 
+impl Drop for Room {
+    /// Keeps the gauges accurate even when the room-owning layer drops a
+    /// `Room` outright (e.g. after it's reaped) instead of walking it
+    /// through `disconnect`/`finish_game` first.
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.rooms_active.dec();
+            for _ in self.user_ids.iter().flatten() {
+                metrics.players_connected.dec();
+            }
+            if matches!(self.match_state, Some(MatchState::Playing(_))) {
+                metrics.games_in_progress.dec();
+            }
+        }
+    }
+}
+
+/// Persists rooms as one JSON file per room so unfinished games survive a
+/// server restart. A returning client still presents its `player_key` to
+/// `rejoin`, which replays the buffered `messages` exactly as it would for a
+/// same-process reconnect.
+///
+/// `Clone` is cheap (it's just the directory path) and expected: construct
+/// one `RoomStore` per process and hand clones of it to every `Room` via
+/// `attach_store`, rather than calling `RoomStore::new` (which re-runs
+/// `fs::create_dir_all`) once per room.
+#[derive(Clone, Debug)]
+pub struct RoomStore {
+    dir: PathBuf,
+}
+
+impl RoomStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(RoomStore { dir })
+    }
+
+    fn path(&self, room_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", room_key))
+    }
+
+    fn tmp_path(&self, room_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json.tmp", room_key))
+    }
+
+    /// Snapshots `room`, or removes its file if the room has finished.
+    /// Call after `connect`, `on_message`, `beat` and `disconnect`.
+    ///
+    /// Writes to a temp file and renames it into place so a crash mid-write
+    /// can never leave a half-written, unreadable room file behind.
+    pub fn save(&self, room: &Room) -> Result<(), Error> {
+        if room.finished() {
+            return self.remove(&room.room_key);
+        }
+
+        let data = serde_json::to_vec(room)?;
+        let tmp_path = self.tmp_path(&room.room_key);
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, self.path(&room.room_key))?;
+        Ok(())
+    }
+
+    pub fn remove(&self, room_key: &str) -> Result<(), Error> {
+        let path = self.path(room_key);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Reloads every unfinished room left over from the previous run.
+    /// `user_ids` comes back empty, same as after a clean `disconnect`, so
+    /// the original players must `rejoin` before they can play again.
+    ///
+    /// A single corrupt or unreadable room file is logged and skipped
+    /// rather than failing the whole reload: losing one stale room is far
+    /// better than losing every room still in the directory.
+    pub fn load_all(&self) -> Result<Vec<Room>, Error> {
+        let mut rooms = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            let room = fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|data| Ok(serde_json::from_slice(&data)?));
+            match room {
+                Ok(room) => rooms.push(room),
+                Err(err) => eprintln!("skipping unreadable room file {}: {}", path.display(), err),
+            }
+        }
+        Ok(rooms)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,15 +819,334 @@ mod tests {
                         culprit: 0,
                         description: "discard too soon".to_owned(),
                     }
-                )
+                ),
+                (
+                    33,
+                    Msg::MatchScore {
+                        scores: [0, 0],
+                        games_played: 1,
+                    }
+                ),
+                (
+                    55,
+                    Msg::MatchScore {
+                        scores: [0, 0],
+                        games_played: 1,
+                    }
+                ),
             ]
         );
+
+        // The series isn't over: the room stays alive waiting for a rematch.
         assert_eq!(room.finished(), false);
-        assert_eq!(room.describe(), None);
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
 
         room.disconnect(33);
         room.disconnect(55);
-        assert_eq!(room.finished(), true);
+        assert_eq!(room.finished(), false);
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
+    }
+
+    #[test]
+    fn test_rematch_requires_both_players() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        room.on_message(33, Msg::Discard { tile: Tile::M1 })
+            .unwrap();
+
+        // Only one side has asked for a rematch so far: no new hand yet.
+        let messages = room.on_message(33, Msg::RequestRematch).unwrap();
+        assert_eq!(messages, vec![]);
+
+        // Once both players consent, a fresh hand starts.
+        let messages = room.on_message(55, Msg::RequestRematch).unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages[0], (33, Msg::PhaseOne { .. })));
+        assert!(matches!(messages[1], (33, Msg::StartMove { .. })));
+        assert!(matches!(messages[2], (55, Msg::PhaseOne { .. })));
+        assert!(matches!(messages[3], (55, Msg::StartMove { .. })));
+    }
+
+    #[test]
+    fn test_match_stays_open_across_hands_with_no_winner() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+
+        // An aborted hand (rule violation, not a real win) never awards a
+        // point, so the series keeps going however many hands are played.
+        for _ in 0..POINTS_TO_WIN + 1 {
+            room.on_message(33, Msg::Discard { tile: Tile::M1 })
+                .unwrap();
+            assert_eq!(room.scores, [0, 0]);
+            assert_eq!(room.finished(), false);
+            room.on_message(33, Msg::RequestRematch).unwrap();
+            room.on_message(55, Msg::RequestRematch).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_forfeit_on_disconnect_timeout() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        room.forfeit_grace = Duration::from_millis(50);
+
+        room.disconnect(55);
+
+        // Grace period hasn't elapsed yet: the opponent isn't stuck waiting
+        // for nothing, but also shouldn't be handed a win prematurely.
+        assert_eq!(room.beat(), vec![]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        let messages = room.beat();
+        assert!(messages.iter().any(|(user_id, msg)| *user_id == 33
+            && matches!(msg, Msg::Abort { culprit: 1, .. })));
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
+    }
+
+    #[test]
+    fn test_forfeit_during_rematch_wait_concludes_series() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        room.forfeit_grace = Duration::from_millis(50);
+
+        // End the first hand, putting the room in WaitingForRematch.
+        room.on_message(33, Msg::Discard { tile: Tile::M1 })
+            .unwrap();
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
+
+        // The opponent vanishes instead of requesting a rematch.
+        room.disconnect(55);
+        assert_eq!(room.beat(), vec![]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        let messages = room.beat();
+        assert!(messages.iter().any(|(user_id, msg)| *user_id == 33
+            && matches!(msg, Msg::Abort { culprit: 1, .. })));
+
+        // The series is over, not just "stuck waiting for a rematch".
         assert_eq!(room.describe(), None);
     }
+
+    #[test]
+    fn test_hand_forfeit_grants_a_fresh_rematch_grace_window() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        room.forfeit_grace = Duration::from_millis(50);
+
+        // Seat 1 disconnects mid-hand and stays gone past the grace period.
+        room.disconnect(55);
+        std::thread::sleep(Duration::from_millis(60));
+
+        // First tick: the hand in progress is forfeited...
+        let messages = room.beat();
+        assert!(messages.iter().any(|(user_id, msg)| *user_id == 33
+            && matches!(msg, Msg::Abort { culprit: 1, .. })));
+        assert_eq!(room.scores, [1, 0]);
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
+
+        // ...but the very next tick must not immediately forfeit the series
+        // too: the absent player gets a fresh grace window to reconnect and
+        // request a rematch, rather than being judged against the same
+        // stale timestamp that just forfeited the hand.
+        assert_eq!(room.beat(), vec![]);
+        assert!(matches!(room.describe(), Some(PGame::Game { .. })));
+    }
+
+    #[test]
+    fn test_chat_relayed_and_replayed() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+
+        let messages = room
+            .on_message(
+                33,
+                Msg::Chat {
+                    from: 0,
+                    text: "gg".to_owned(),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            messages,
+            vec![(
+                55,
+                Msg::Chat {
+                    from: 0,
+                    text: "gg".to_owned(),
+                }
+            )]
+        );
+
+        // A rate-limited follow-up is rejected...
+        assert!(room
+            .on_message(
+                33,
+                Msg::Chat {
+                    from: 0,
+                    text: "gg again".to_owned(),
+                },
+            )
+            .is_err());
+
+        // ...and an overlong message is rejected outright.
+        room.last_chat[0] = None;
+        assert!(room
+            .on_message(
+                33,
+                Msg::Chat {
+                    from: 0,
+                    text: "x".repeat(CHAT_MAX_LEN + 1),
+                },
+            )
+            .is_err());
+
+        // The chat backlog replays to a reconnecting player.
+        room.disconnect(55);
+        let messages = room.rejoin(55, 1).unwrap();
+        assert!(messages
+            .iter()
+            .any(|(_, msg)| matches!(replayed(msg), Some(Msg::Chat { text, .. }) if text == "gg")));
+    }
+
+    #[test]
+    fn test_metrics_track_room_lifecycle() {
+        let registry = Registry::new();
+        let metrics = RoomMetrics::register(&registry).unwrap();
+
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.attach_metrics(metrics.clone());
+        assert_eq!(metrics.rooms_active.get(), 1);
+        assert_eq!(metrics.players_connected.get(), 1);
+        assert_eq!(metrics.games_in_progress.get(), 0);
+
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        assert_eq!(metrics.players_connected.get(), 2);
+        assert_eq!(metrics.games_in_progress.get(), 1);
+
+        room.disconnect(55);
+        assert_eq!(metrics.players_connected.get(), 1);
+
+        drop(room);
+        assert_eq!(metrics.rooms_active.get(), 0);
+        assert_eq!(metrics.players_connected.get(), 0);
+        assert_eq!(metrics.games_in_progress.get(), 0);
+    }
+
+    #[test]
+    fn test_room_store_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("minefield-room-store-test-{}", 1));
+        let _ = fs::remove_dir_all(&dir);
+        let store = RoomStore::new(&dir).unwrap();
+
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        let room_key = room.room_key.clone();
+
+        store.save(&room).unwrap();
+        let loaded = store.load_all().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].room_key, room_key);
+        assert_eq!(loaded[0].user_ids, [None, None]);
+
+        store.remove(&room_key).unwrap();
+        assert_eq!(store.load_all().unwrap().len(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_room_store_skips_unreadable_files_instead_of_failing_whole_reload() {
+        let dir = std::env::temp_dir().join(format!("minefield-room-store-corrupt-test-{}", 1));
+        let _ = fs::remove_dir_all(&dir);
+        let store = RoomStore::new(&dir).unwrap();
+
+        let room = Room::new(33, "Akagi".to_owned());
+        store.save(&room).unwrap();
+
+        // A corrupt or partially-written file alongside a valid one
+        // shouldn't take the whole reload down with it.
+        fs::write(dir.join("garbage.json"), b"not json").unwrap();
+
+        let loaded = store.load_all().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].room_key, room.room_key);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concluded_series_is_not_reopened_as_a_fresh_room() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+
+        // The series has concluded and the loser (seat 1) already left, but
+        // the winner (seat 0) is still connected.
+        room.match_state = Some(MatchState::Concluded);
+        room.user_ids[1] = None;
+
+        // This must not be mistaken for a never-started room with an open
+        // seat, or a stranger could hijack it.
+        assert_eq!(room.describe(), None);
+
+        let err = room.connect(77, "Stranger".to_owned()).unwrap_err();
+        assert_eq!(err.to_string(), "match series already concluded");
+    }
+
+    #[test]
+    fn test_room_store_persists_automatically_after_mutations() {
+        let dir = std::env::temp_dir().join(format!("minefield-room-store-auto-test-{}", 1));
+        let _ = fs::remove_dir_all(&dir);
+        let store = RoomStore::new(&dir).unwrap();
+
+        // One `RoomStore` handle, cloned into every room it backs — this is
+        // the expected way to share a single on-disk directory.
+        let mut room = Room::new(33, "Akagi".to_owned());
+        let room_key = room.room_key.clone();
+        room.attach_store(store.clone());
+
+        let mut other_room = Room::new(77, "Wakamoto".to_owned());
+        let other_room_key = other_room.room_key.clone();
+        other_room.attach_store(store.clone());
+
+        room.connect(55, "Washizu".to_owned()).unwrap();
+        assert_eq!(store.load_all().unwrap().len(), 2);
+
+        room.on_message(
+            33,
+            Msg::Chat {
+                from: 0,
+                text: "gg".to_owned(),
+            },
+        )
+        .unwrap();
+        let loaded = store.load_all().unwrap();
+        assert!(loaded.iter().any(|r| r.room_key == room_key));
+        assert!(loaded.iter().any(|r| r.room_key == other_room_key));
+
+        room.disconnect(55);
+        room.beat();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_connect_spectator() {
+        let mut room = Room::new(33, "Akagi".to_owned());
+        room.connect(55, "Washizu".to_owned()).unwrap();
+
+        // A spectator joining mid-game replays the merged, redacted history
+        // instead of either player's raw (and secret-bearing) stream.
+        let replayed = room.connect_spectator(99);
+        assert!(replayed.iter().all(|(user_id, _)| *user_id == 99));
+        assert!(replayed.len() < room.messages[0].len() + room.messages[1].len());
+
+        let messages = room
+            .on_message(33, Msg::Discard { tile: Tile::M1 })
+            .unwrap();
+        assert!(messages
+            .iter()
+            .any(|(user_id, msg)| *user_id == 99 && matches!(msg, Msg::Abort { .. })));
+
+        room.disconnect_spectator(99);
+    }
 }